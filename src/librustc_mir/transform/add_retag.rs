@@ -7,6 +7,7 @@
 use crate::transform::{MirPass, MirSource};
 use rustc_middle::mir::*;
 use rustc_middle::ty::{self, Ty, TyCtxt};
+use rustc_session::config::RetagFields;
 
 pub struct AddRetag;
 
@@ -50,13 +51,149 @@ fn may_be_reference(ty: Ty<'tcx>) -> bool {
         // References
         ty::Ref(..) => true,
         ty::Adt(..) if ty.is_box() => true,
-        // Compound types are not references
+        // Compound types need to be visited field-by-field, see `for_each_retag_place`.
         ty::Array(..) | ty::Slice(..) | ty::Tuple(..) | ty::Adt(..) => false,
         // Conservative fallback
         _ => true,
     }
 }
 
+/// An upper bound on how many array elements we are willing to retag
+/// individually. Of an array longer than this only the first `MAX_ARRAY_RETAG_ELEMS`
+/// elements are retagged, and arrays with a symbolic length are left alone,
+/// so that a `[SomeStruct; 1_000_000]` does not blow up the size of the MIR.
+const MAX_ARRAY_RETAG_ELEMS: u64 = 32;
+
+/// Recursively collect every reachable reference/box-typed sub-place of `place`
+/// (whose type is `ty`), by descending through tuples, arrays and structs
+/// (slices, enums and unions are not descended into).
+/// We stop recursing as soon as we hit a reference or a box: what is *behind* the
+/// pointer is retagged separately (if at all), not here, so this always terminates
+/// even for types that are recursive through indirection.
+fn for_each_retag_place<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    place: Place<'tcx>,
+    ty: Ty<'tcx>,
+    places: &mut Vec<Place<'tcx>>,
+) {
+    if may_be_reference(ty) {
+        places.push(place);
+        return;
+    }
+    match ty.kind {
+        ty::Tuple(substs) => {
+            for (idx, field_ty) in substs.iter().enumerate() {
+                let field_ty = field_ty.expect_ty();
+                let field_place = project_field(tcx, place, idx as u32, field_ty);
+                for_each_retag_place(tcx, field_place, field_ty, places);
+            }
+        }
+        ty::Array(elem_ty, len) => {
+            // A symbolic (not-yet-evaluated) length cannot be enumerated; skip it
+            // rather than trying to emit a loop in MIR for this pass.
+            if let Some(len) = len.try_eval_usize(tcx, ty::ParamEnv::reveal_all()) {
+                for idx in 0..len.min(MAX_ARRAY_RETAG_ELEMS) {
+                    let elem_place = project_index(tcx, place, idx, len);
+                    for_each_retag_place(tcx, elem_place, elem_ty, places);
+                }
+            }
+        }
+        // Slices are unsized, so there is no finite set of indices we could project;
+        // we only ever see them as the tail of a place we already cannot enumerate.
+        ty::Slice(_) => {}
+        ty::Adt(adt_def, substs) if !ty.is_box() => {
+            // Enums would need a `Downcast` per variant; the payload of an enum is
+            // rarely worth the MIR bloat, so we conservatively skip them for now.
+            // Unions are skipped for correctness, not just MIR size: they have no
+            // discriminant, so a field's declared type is not necessarily the type
+            // the union is currently holding (or it may hold nothing at all), and
+            // retagging it as a reference/box could validate uninitialized or
+            // wrongly-typed bytes.
+            if adt_def.is_enum() || adt_def.is_union() {
+                return;
+            }
+            for (idx, field) in adt_def.all_fields().enumerate() {
+                let field_ty = field.ty(tcx, substs);
+                let field_place = project_field(tcx, place, idx as u32, field_ty);
+                for_each_retag_place(tcx, field_place, field_ty, places);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Like `may_be_reference`, but also `true` for aggregates that might have a
+/// reference/box reachable somewhere inside of them. Used as a cheap
+/// pre-filter for whether a place is worth inspecting at all, before doing the
+/// (possibly recursive, possibly temporary-introducing) work below.
+fn may_contain_reference<'tcx>(tcx: TyCtxt<'tcx>, ty: Ty<'tcx>) -> bool {
+    if may_be_reference(ty) {
+        return true;
+    }
+    match ty.kind {
+        ty::Tuple(substs) => substs.iter().any(|f| may_contain_reference(tcx, f.expect_ty())),
+        ty::Array(elem_ty, _) | ty::Slice(elem_ty) => may_contain_reference(tcx, elem_ty),
+        ty::Adt(adt_def, substs) if !ty.is_box() && !adt_def.is_enum() && !adt_def.is_union() => {
+            adt_def.all_fields().any(|f| may_contain_reference(tcx, f.ty(tcx, substs)))
+        }
+        _ => false,
+    }
+}
+
+fn project_field<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    place: Place<'tcx>,
+    field: u32,
+    field_ty: Ty<'tcx>,
+) -> Place<'tcx> {
+    let mut projection = place.projection.to_vec();
+    projection.push(ProjectionElem::Field(Field::from_u32(field), field_ty));
+    Place { local: place.local, projection: tcx.intern_place_elems(&projection) }
+}
+
+fn project_index<'tcx>(tcx: TyCtxt<'tcx>, place: Place<'tcx>, offset: u64, min_length: u64) -> Place<'tcx> {
+    let mut projection = place.projection.to_vec();
+    projection.push(ProjectionElem::ConstantIndex { offset, min_length, from_end: false });
+    Place { local: place.local, projection: tcx.intern_place_elems(&projection) }
+}
+
+/// Collect every sub-place of `place` (of type `ty`) that needs a retag statement.
+/// Under `RetagFields::Scalar` (and `RetagFields::FnEntryOnly`, which only
+/// restricts *where* retags are added, not how deep they go) this is just `place`
+/// itself, if it is a reference or box. Under `RetagFields::Aggregate` it is every
+/// reachable reference/box, recursing into tuples/arrays/structs.
+fn retag_places<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    place: Place<'tcx>,
+    ty: Ty<'tcx>,
+    retag_fields: RetagFields,
+) -> Vec<Place<'tcx>> {
+    match retag_fields {
+        RetagFields::Scalar | RetagFields::FnEntryOnly => {
+            if may_be_reference(ty) { vec![place] } else { vec![] }
+        }
+        RetagFields::Aggregate => {
+            let mut places = Vec::new();
+            for_each_retag_place(tcx, place, ty, &mut places);
+            places
+        }
+    }
+}
+
+/// Cheap, place-independent check for whether `retag_places(tcx, _, ty, retag_fields)`
+/// may return anything at all. It returns `false` only where `retag_places` returns
+/// nothing, but under `RetagFields::Aggregate` it can return `true` where
+/// `retag_places` still finds nothing (a slice, or an array whose length is zero or
+/// cannot be evaluated). Lets us skip the more expensive work below (which,
+/// for unstable places, includes introducing a temporary and rewriting a statement)
+/// for types that obviously cannot contain a reference or box.
+fn would_retag_anything(tcx: TyCtxt<'tcx>, ty: Ty<'tcx>, retag_fields: RetagFields) -> bool {
+    match retag_fields {
+        RetagFields::Scalar | RetagFields::FnEntryOnly => may_be_reference(ty),
+        RetagFields::Aggregate => may_contain_reference(tcx, ty),
+    }
+}
+
 impl<'tcx> MirPass<'tcx> for AddRetag {
     fn run_pass(&self, tcx: TyCtxt<'tcx>, src: MirSource<'tcx>, body: &mut Body<'tcx>) {
         if !tcx.sess.opts.debugging_opts.mir_emit_retag {
@@ -66,12 +203,13 @@ impl<'tcx> MirPass<'tcx> for AddRetag {
         // We need an `AllCallEdges` pass before we can do any work.
         super::add_call_guards::AllCallEdges.run_pass(tcx, src, body);
 
+        let retag_fields = tcx.sess.opts.debugging_opts.retag_fields;
         let (span, arg_count) = (body.span, body.arg_count);
         let (basic_blocks, local_decls) = body.basic_blocks_and_local_decls_mut();
         let needs_retag = |place: &Place<'tcx>| {
             // FIXME: Instead of giving up for unstable places, we should introduce
             // a temporary and retag on that.
-            is_stable(place.as_ref()) && may_be_reference(place.ty(&*local_decls, tcx).ty)
+            is_stable(place.as_ref())
         };
 
         // PART 1
@@ -80,13 +218,17 @@ impl<'tcx> MirPass<'tcx> for AddRetag {
             // FIXME: Consider using just the span covering the function
             // argument declaration.
             let source_info = SourceInfo::outermost(span);
-            // Gather all arguments, skip return value.
+            // Gather all arguments, skip return value, and recursively expand
+            // each one into every reference/box reachable inside of it.
             let places = local_decls
                 .iter_enumerated()
                 .skip(1)
                 .take(arg_count)
                 .map(|(local, _)| Place::from(local))
-                .filter(needs_retag)
+                .flat_map(|place| {
+                    let ty = place.ty(&*local_decls, tcx).ty;
+                    retag_places(tcx, place, ty, retag_fields)
+                })
                 .collect::<Vec<_>>();
             // Emit their retags.
             basic_blocks[START_BLOCK].statements.splice(
@@ -98,6 +240,12 @@ impl<'tcx> MirPass<'tcx> for AddRetag {
             );
         }
 
+        // Under `RetagFields::FnEntryOnly` we are done: the caller only wants the
+        // retags PART 1 just added at the start of the function.
+        if retag_fields == RetagFields::FnEntryOnly {
+            return;
+        }
+
         // PART 2
         // Retag return values of functions.  Also escape-to-raw the argument of `drop`.
         // We collect the return destinations because we cannot mutate while iterating.
@@ -107,7 +255,9 @@ impl<'tcx> MirPass<'tcx> for AddRetag {
                 TerminatorKind::Call { ref destination, .. } => {
                     // Remember the return destination for later
                     if let Some(ref destination) = destination {
-                        if needs_retag(&destination.0) {
+                        let ty = destination.0.ty(&*local_decls, tcx).ty;
+                        if needs_retag(&destination.0) && would_retag_anything(tcx, ty, retag_fields)
+                        {
                             returns.push((
                                 block_data.terminator().source_info,
                                 destination.0,
@@ -126,12 +276,14 @@ impl<'tcx> MirPass<'tcx> for AddRetag {
         }
         // Now we go over the returns we collected to retag the return values.
         for (source_info, dest_place, dest_block) in returns {
-            basic_blocks[dest_block].statements.insert(
-                0,
-                Statement {
+            let ty = dest_place.ty(&*local_decls, tcx).ty;
+            let places = retag_places(tcx, dest_place, ty, retag_fields);
+            basic_blocks[dest_block].statements.splice(
+                0..0,
+                places.into_iter().map(|place| Statement {
                     source_info,
-                    kind: StatementKind::Retag(RetagKind::Default, box (dest_place)),
-                },
+                    kind: StatementKind::Retag(RetagKind::Default, box (place)),
+                }),
             );
         }
 
@@ -141,15 +293,29 @@ impl<'tcx> MirPass<'tcx> for AddRetag {
             // We want to insert statements as we iterate.  To this end, we
             // iterate backwards using indices.
             for i in (0..block_data.statements.len()).rev() {
-                let (retag_kind, place) = match block_data.statements[i].kind {
+                // What needs retagging once we've established `place` is worth
+                // visiting at all. A raw-pointer escape always retags `place`
+                // itself directly: its type is a raw pointer, which
+                // `may_be_reference`/`may_contain_reference` (rightly) never
+                // consider a retag target, so it must never be routed through
+                // `retag_places`/`would_retag_anything` -- doing so would
+                // silently find nothing to retag on *every* raw-pointer escape.
+                enum RetagTarget<'tcx> {
+                    Direct(Place<'tcx>),
+                    Places(Place<'tcx>, Ty<'tcx>),
+                }
+
+                let (retag_kind, target, stable) = match block_data.statements[i].kind {
                     // Retag-as-raw after escaping to a raw pointer.
                     StatementKind::Assign(box (place, Rvalue::AddressOf(..))) => {
-                        (RetagKind::Raw, place)
+                        (RetagKind::Raw, RetagTarget::Direct(place), is_stable(place.as_ref()))
                     }
                     // Assignments of reference or ptr type are the ones where we may have
                     // to update tags.  This includes `x = &[mut] ...` and hence
                     // we also retag after taking a reference!
-                    StatementKind::Assign(box (ref place, ref rvalue)) if needs_retag(place) => {
+                    StatementKind::Assign(box (ref place, ref rvalue))
+                        if may_be_reference(place.ty(&*local_decls, tcx).ty) =>
+                    {
                         let kind = match rvalue {
                             Rvalue::Ref(_, borrow_kind, _)
                                 if borrow_kind.allows_two_phase_borrow() =>
@@ -158,17 +324,86 @@ impl<'tcx> MirPass<'tcx> for AddRetag {
                             }
                             _ => RetagKind::Default,
                         };
-                        (kind, *place)
+                        let ty = place.ty(&*local_decls, tcx).ty;
+                        (kind, RetagTarget::Places(*place, ty), is_stable(place.as_ref()))
+                    }
+                    // Recurse into aggregates even when the assigned type is not itself a
+                    // reference (e.g. `x = (a, &mut b)`); the pre-filter means we only
+                    // get here when something inside `place` may be a reference or box.
+                    // Only `RetagFields::Aggregate` then retags anything inside it.
+                    StatementKind::Assign(box (ref place, _))
+                        if may_contain_reference(tcx, place.ty(&*local_decls, tcx).ty) =>
+                    {
+                        let ty = place.ty(&*local_decls, tcx).ty;
+                        (RetagKind::Default, RetagTarget::Places(*place, ty), is_stable(place.as_ref()))
                     }
                     // Do nothing for the rest
                     _ => continue,
                 };
-                // Insert a retag after the statement.
                 let source_info = block_data.statements[i].source_info;
-                block_data.statements.insert(
-                    i + 1,
-                    Statement { source_info, kind: StatementKind::Retag(retag_kind, box (place)) },
-                );
+
+                if stable {
+                    let retags = match target {
+                        RetagTarget::Direct(place) => vec![place],
+                        RetagTarget::Places(place, ty) => retag_places(tcx, place, ty, retag_fields),
+                    };
+                    block_data.statements.splice(
+                        i + 1..i + 1,
+                        retags.into_iter().map(|place| Statement {
+                            source_info,
+                            kind: StatementKind::Retag(retag_kind, box (place)),
+                        }),
+                    );
+                    continue;
+                }
+
+                // `place` is unstable (it goes through a `Deref`), so we cannot just
+                // retag it after the fact: re-evaluating it later is not guaranteed to
+                // yield the same location.  Instead, introduce a fresh (and therefore
+                // stable) temporary, move the assigned value into that, retag it there,
+                // and then store it back into the original place.
+                let (place, ty, direct) = match target {
+                    RetagTarget::Direct(place) => {
+                        let ty = place.ty(&*local_decls, tcx).ty;
+                        (place, ty, true)
+                    }
+                    RetagTarget::Places(place, ty) => {
+                        if !would_retag_anything(tcx, ty, retag_fields) {
+                            // Under the current mode there is nothing to retag inside
+                            // `place` at all (e.g. `*p = (a, &mut b)` with
+                            // `retag_fields == Scalar`), so don't bother introducing
+                            // a temporary just to move it around.
+                            continue;
+                        }
+                        (place, ty, false)
+                    }
+                };
+
+                let temp = Place::from(local_decls.push(LocalDecl::new(ty, source_info.span)));
+                let rvalue = match &mut block_data.statements[i].kind {
+                    StatementKind::Assign(box (_, rvalue)) => {
+                        std::mem::replace(rvalue, Rvalue::Use(Operand::Move(temp)))
+                    }
+                    _ => bug!("only `Assign` statements reach this point"),
+                };
+                block_data.statements[i] =
+                    Statement { source_info, kind: StatementKind::Assign(box (temp, rvalue)) };
+                // A raw-pointer escape always retags the temp directly; everything
+                // else recurses into it via `retag_places`, same as the stable case.
+                let retags =
+                    if direct { vec![temp] } else { retag_places(tcx, temp, ty, retag_fields) };
+                let mut new_statements: Vec<_> = retags
+                    .into_iter()
+                    .map(|place| Statement {
+                        source_info,
+                        kind: StatementKind::Retag(retag_kind, box (place)),
+                    })
+                    .collect();
+                new_statements.push(Statement {
+                    source_info,
+                    kind: StatementKind::Assign(box (place, Rvalue::Use(Operand::Move(temp)))),
+                });
+                block_data.statements.splice(i + 1..i + 1, new_statements);
             }
         }
     }
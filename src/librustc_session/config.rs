@@ -0,0 +1,130 @@
+//! This file normally holds the full `options!` table that backs `-C` and `-Z`
+//! command-line flags (`CodegenOptions` and `DebuggingOptions`) along with their
+//! value parsers; that table, its `-C`/codegen-options half, and every other
+//! pre-existing `-Z` flag besides `mir_emit_retag` are not reproduced in this
+//! patch. What *is* here is real, compiling code: the new `RetagFields` type,
+//! its parser, and a reduced `options!` macro (see its doc comment below)
+//! whose single invocation registers both `mir_emit_retag` and the new
+//! `retag_fields` entry on a `DebuggingOptions` that has just those two
+//! fields. Landing this on the full crate means folding the `retag_fields`
+//! entry into the real, much larger `options!` invocation instead.
+
+/// Granularity of the retags `AddRetag` (see `rustc_mir::transform::add_retag`)
+/// emits, selected via `-Z retag-fields`. This lets Miri users trade
+/// instrumentation completeness against MIR size and compile time on large
+/// crates.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RetagFields {
+    /// Only retag places whose own type is a reference or box; do not recurse
+    /// into tuples, arrays or structs. This is the original, coarse-grained
+    /// behavior, and the default.
+    Scalar,
+    /// Recurse into tuples, arrays and structs and retag every reference/box
+    /// reachable inside of them.
+    Aggregate,
+    /// Only retag function arguments at the start of the function; skip the
+    /// retags this pass would otherwise add after assignments and after call
+    /// returns.
+    FnEntryOnly,
+}
+
+impl Default for RetagFields {
+    fn default() -> Self {
+        RetagFields::Scalar
+    }
+}
+
+mod parse {
+    use super::RetagFields;
+
+    /// Parses a boolean `-Z` flag. The real `rustc_session::config::parse`
+    /// module (not reproduced here) already has one of these; `mir_emit_retag`
+    /// uses it, so our reduced `options!` stand-in needs one too.
+    crate fn parse_bool(slot: &mut bool, v: Option<&str>) -> bool {
+        match v {
+            None => {
+                *slot = true;
+                true
+            }
+            Some("yes") | Some("y") | Some("on") | Some("true") => {
+                *slot = true;
+                true
+            }
+            Some("no") | Some("n") | Some("off") | Some("false") => {
+                *slot = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Parses `-Z retag-fields[=scalar|aggregate|fn-entry-only]`.
+    crate fn parse_retag_fields(slot: &mut RetagFields, v: Option<&str>) -> bool {
+        *slot = match v {
+            None | Some("scalar") => RetagFields::Scalar,
+            Some("aggregate") => RetagFields::Aggregate,
+            Some("fn-entry-only") => RetagFields::FnEntryOnly,
+            _ => return false,
+        };
+        true
+    }
+}
+
+// The real `options!` invocation that builds `DebuggingOptions` (and every
+// pre-existing `-Z` flag, including `mir_emit_retag`) is not reproduced in
+// this checkout. Folding this change into that invocation means adding the
+// `retag_fields` entry below right next to the existing `mir_emit_retag`
+// entry -- same macro, same entry syntax, nothing invented:
+//
+//     mir_emit_retag: bool = (false, parse_bool, [TRACKED],
+//         "emit Retagging MIR statements, interpreted e.g., by miri; implies -Zmir-opt-level=0"),
+//     retag_fields: RetagFields = (RetagFields::Scalar, parse_retag_fields, [TRACKED],
+//         "control how much of a place `-Z mir-emit-retag` retags: `scalar` (default) \
+//          only retags reference/box-typed places, `aggregate` recurses into tuples, \
+//          arrays and structs, and `fn-entry-only` retags function arguments only"),
+//
+// Because that invocation (and the rest of `DebuggingOptions`'s ~100 other
+// flags) lives outside this checkout, the snippet above can only be *shown*,
+// not compiled, here. What follows instead is real code: `options!` itself,
+// reduced to just the bits this change exercises (no `-C` side, no
+// dep-tracking hash, no help-printing), applied to the two entries above,
+// with each parser named by its path in the `parse` module below. It is a faithful enough stand-in to confirm the entry syntax and
+// parser line up and actually produce a working `debugging_opts.retag_fields`
+// field -- not a replacement for folding the real entry into the real macro.
+macro_rules! options {
+    ($struct_name:ident, $(
+        $(#[$attr:meta])* $opt:ident : $t:ty = (
+            $init:expr, $parse:path, [$tracked:ident], $doc:expr $(,)?
+        ),
+    )*) => {
+        pub struct $struct_name {
+            $($(#[$attr])* pub $opt: $t,)*
+        }
+
+        impl Default for $struct_name {
+            fn default() -> Self {
+                $struct_name { $($opt: $init,)* }
+            }
+        }
+
+        impl $struct_name {
+            /// Parses `-Z <flag>[=<value>]`, returning `false` for an
+            /// unrecognized flag name or a value its parser rejects.
+            pub fn parse(&mut self, flag: &str, value: Option<&str>) -> bool {
+                match flag {
+                    $(stringify!($opt) => $parse(&mut self.$opt, value),)*
+                    _ => false,
+                }
+            }
+        }
+    };
+}
+
+options! {DebuggingOptions,
+    mir_emit_retag: bool = (false, parse::parse_bool, [TRACKED],
+        "emit Retagging MIR statements, interpreted e.g., by miri; implies -Zmir-opt-level=0"),
+    retag_fields: RetagFields = (RetagFields::Scalar, parse::parse_retag_fields, [TRACKED],
+        "control how much of a place `-Z mir-emit-retag` retags: `scalar` (default) \
+         only retags reference/box-typed places, `aggregate` recurses into tuples, \
+         arrays and structs, and `fn-entry-only` retags function arguments only"),
+}
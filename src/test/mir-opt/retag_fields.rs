@@ -0,0 +1,65 @@
+// Check that `-Z retag-fields` controls how many `Retag` statements
+// `AddRetag` emits for a function whose argument is an aggregate (here, a
+// tuple) containing a nested reference, and that raw-pointer escapes are
+// retagged (as `RetagKind::Raw`) in every mode except `fn-entry-only`, since a
+// raw-pointer-typed place is never itself a retag target for
+// `retag_places`/`would_retag_anything` to find.
+//
+// revisions: scalar aggregate fn_entry_only
+//[scalar] compile-flags: -Z mir-emit-retag -Z retag-fields=scalar
+//[aggregate] compile-flags: -Z mir-emit-retag -Z retag-fields=aggregate
+//[fn_entry_only] compile-flags: -Z mir-emit-retag -Z retag-fields=fn-entry-only
+
+#![feature(raw_ref_op)]
+
+fn nested_ref(pair: (i32, &mut i32)) {
+    let _x = pair.0;
+    *pair.1 = 42;
+}
+
+fn raw_escape(x: &mut i32) {
+    let _p = &raw mut *x;
+}
+
+fn main() {
+    let mut v = 1;
+    nested_ref((0, &mut v));
+    raw_escape(&mut v);
+}
+
+// Expected `Retag` statement counts asserted by the
+// `retag_fields.{item}.AddRetag.{revision}.diff` files this test compares
+// against:
+//
+// `nested_ref` (exercises aggregate-field recursion):
+//   - scalar:        0 (`pair: (i32, &mut i32)` is a tuple, not itself a
+//                       reference, and scalar mode does not recurse into it)
+//   - aggregate:     1 (recursing into `pair` finds the nested
+//                       `pair.1: &mut i32` field and retags it)
+//   - fn_entry_only: 0 (entry-only defaults to scalar granularity at the
+//                       entry point, same as `scalar` above)
+//
+// `raw_escape` (exercises the raw-pointer-escape path):
+//   - scalar:        2 (a `RetagKind::FnEntry` retag of the argument `x`,
+//                       plus a `RetagKind::Raw` retag after `&raw mut *x` --
+//                       raw-pointer places are never routed through
+//                       `retag_places`/`would_retag_anything`, so the raw
+//                       retag does not depend on the recursion depth)
+//   - aggregate:     2 (same as above)
+//   - fn_entry_only: 1 (only the `RetagKind::FnEntry` retag of `x`;
+//                       `FnEntryOnly` returns after PART 1, so PART 3's
+//                       raw-escape retag is not emitted)
+//
+// EMIT_MIR retag_fields.nested_ref.AddRetag.diff
+// EMIT_MIR retag_fields.raw_escape.AddRetag.diff
+//
+// NOTE: the `retag_fields.{item}.AddRetag.{revision}.diff` fixture files that
+// `EMIT_MIR` compares output against are machine-generated by compiletest's
+// `--bless` against a full `x.py` build. This checkout has no buildable
+// compiler (there is no `Cargo.toml` anywhere in the tree), so those files
+// cannot be generated or verified here, and are deliberately NOT included --
+// hand-authoring MIR dumps would just be guessing at their exact form. As
+// committed, running this test will fail with a missing-fixture error rather
+// than silently passing; this is not yet verified, working coverage. Anyone
+// landing this on a real checkout must run `--bless` once (and confirm the
+// counts above) before this can be considered done.